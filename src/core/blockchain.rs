@@ -1,9 +1,18 @@
+use std::cmp;
+use std::collections::HashSet;
+
 use bincode::{serialize, Infinite};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use chrono::prelude::*;
+use secp256k1::{Message, RecoveryId, Secp256k1};
+use secp256k1::key::PublicKey;
+use secp256k1::RecoverableSignature;
 
+use core::mempool::{MemoryPool, MemoryPoolInfo, OrderingStrategy};
 use core::nodemanager::P2PNodeManager;
+use core::queue::{BlockQueue, QueueInfo};
+use core::storage::{Storage, StorageError};
 
 pub type Chain = Vec<Block>;
 
@@ -11,121 +20,556 @@ pub type Chain = Vec<Block>;
 pub struct Block {
     index: u32,
     timestamp: i64,
-    transactions: Vec<Transaction>,
+    transactions: Vec<VerifiedTransaction>,
     proof: u64,
     previous_hash: String,
+    /// Number of required trailing zero nibbles in a block's proof hash.
+    /// Retargeted every `Blockchain::RETARGET_INTERVAL` blocks.
+    difficulty: u32,
 }
 
+impl Block {
+    pub(crate) fn from_parts(
+        index: u32,
+        timestamp: i64,
+        transactions: Vec<VerifiedTransaction>,
+        proof: u64,
+        previous_hash: String,
+        difficulty: u32,
+    ) -> Block {
+        Block {
+            index,
+            timestamp,
+            transactions,
+            proof,
+            previous_hash,
+            difficulty,
+        }
+    }
+
+    pub(crate) fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub(crate) fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub(crate) fn proof(&self) -> u64 {
+        self.proof
+    }
+
+    pub(crate) fn previous_hash(&self) -> &str {
+        &self.previous_hash
+    }
+
+    pub(crate) fn transactions(&self) -> &[VerifiedTransaction] {
+        &self.transactions
+    }
+
+    pub(crate) fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+}
+
+/// A transaction as received over the wire (e.g. the body of the `/transaction`
+/// route). The `signature` has not been checked yet, and `sender` is only a
+/// claim until `recover_sender()` proves it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnverifiedTransaction {
+    pub sender: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub nonce: u64,
+    /// Paid to whichever node mines this transaction, on top of the fixed
+    /// block reward. Used by the mempool to prioritize which pending
+    /// transactions are selected first; see `core::mempool`.
+    pub fee: u64,
+    pub signature: String,
+}
+
+/// A transaction whose signature has been checked against its declared
+/// `sender`. Only `VerifiedTransaction`s are ever stored in a `Block`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Transaction {
+pub struct VerifiedTransaction {
     pub sender: String,
     pub recipient: String,
     pub amount: u64,
+    pub nonce: u64,
+    pub fee: u64,
+    pub signature: String,
 }
 
-impl Transaction {
-    pub fn new(sender: String, recipient: String, amount: u64) -> Transaction {
-        Transaction {
+#[derive(Debug)]
+pub enum TransactionError {
+    MalformedSignature,
+    SenderMismatch,
+    DuplicateTransaction,
+    ForgedCoinbase,
+    StorageFailure(StorageError),
+}
+
+impl From<StorageError> for TransactionError {
+    fn from(err: StorageError) -> TransactionError {
+        TransactionError::StorageFailure(err)
+    }
+}
+
+/// Describes what a `Blockchain::resolve_conflicts` reorg actually did:
+/// which blocks were dropped (`retracted`) and added (`enacted`), and how
+/// many of the retracted transactions were pushed back into the mempool.
+#[derive(Serialize, Debug, Clone)]
+pub struct ImportRoute {
+    pub enacted: Vec<String>,
+    pub retracted: Vec<String>,
+    pub readded_transactions: usize,
+}
+
+impl ImportRoute {
+    fn none() -> ImportRoute {
+        ImportRoute {
+            enacted: Vec::new(),
+            retracted: Vec::new(),
+            readded_transactions: 0,
+        }
+    }
+}
+
+impl UnverifiedTransaction {
+    pub fn new(
+        sender: String,
+        recipient: String,
+        amount: u64,
+        nonce: u64,
+        fee: u64,
+        signature: String,
+    ) -> UnverifiedTransaction {
+        UnverifiedTransaction {
             sender,
             recipient,
             amount,
+            nonce,
+            fee,
+            signature,
+        }
+    }
+
+    /// Checks `signature` against the canonical `(recipient, amount, nonce,
+    /// fee)` payload and recovers the signer's address. Coinbase transactions
+    /// (`sender == Blockchain::ORIGIN_SENDER`) are exempt and pass through
+    /// unchecked, matching the reward path in `Blockchain::mine`.
+    pub fn recover_sender(&self) -> Result<VerifiedTransaction, TransactionError> {
+        if self.sender == Blockchain::ORIGIN_SENDER {
+            return Ok(self.clone().into_verified());
+        }
+
+        let digest = Blockchain::signing_digest(&self.recipient, self.amount, self.nonce, self.fee);
+
+        let sig_bytes = from_hex(&self.signature).map_err(|_| TransactionError::MalformedSignature)?;
+        if sig_bytes.len() != 65 {
+            return Err(TransactionError::MalformedSignature);
+        }
+
+        let recovery_id = RecoveryId::from_i32(sig_bytes[0] as i32)
+            .map_err(|_| TransactionError::MalformedSignature)?;
+        let recoverable_sig = RecoverableSignature::from_compact(&sig_bytes[1..], recovery_id)
+            .map_err(|_| TransactionError::MalformedSignature)?;
+        let message = Message::from_slice(&digest).map_err(|_| TransactionError::MalformedSignature)?;
+
+        let secp = Secp256k1::new();
+        let pubkey = secp
+            .recover(&message, &recoverable_sig)
+            .map_err(|_| TransactionError::MalformedSignature)?;
+
+        if address_from_pubkey(&pubkey) != self.sender {
+            return Err(TransactionError::SenderMismatch);
+        }
+
+        Ok(self.clone().into_verified())
+    }
+
+    fn into_verified(self) -> VerifiedTransaction {
+        VerifiedTransaction {
+            sender: self.sender,
+            recipient: self.recipient,
+            amount: self.amount,
+            nonce: self.nonce,
+            fee: self.fee,
+            signature: self.signature,
         }
     }
 }
 
+impl VerifiedTransaction {
+    fn into_unverified(self) -> UnverifiedTransaction {
+        UnverifiedTransaction {
+            sender: self.sender,
+            recipient: self.recipient,
+            amount: self.amount,
+            nonce: self.nonce,
+            fee: self.fee,
+            signature: self.signature,
+        }
+    }
+}
+
+fn address_from_pubkey(pubkey: &PublicKey) -> String {
+    let mut sha = Sha256::new();
+    sha.input(&pubkey.serialize());
+    sha.result_str()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, ()> {
+    if s.len() % 2 != 0 {
+        return Err(());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| ()))
+        .collect()
+}
+
 pub struct Blockchain {
     pub chain: Chain,
     pub node_manager: P2PNodeManager,
-    pending_transactions: Vec<Transaction>,
+    mempool: MemoryPool,
+    storage: Storage,
+    last_block: Block,
+    block_queue: BlockQueue,
+    /// Signatures of every non-coinbase transaction mined into `chain`, used
+    /// to reject replays of an already-confirmed transaction even after it's
+    /// fallen out of the mempool. Keyed on signature rather than `(sender,
+    /// nonce)` since that's already the unique identity `recover_sender`
+    /// checks against.
+    confirmed_signatures: HashSet<String>,
 }
 
 impl Blockchain {
     const ORIGIN_SENDER: &'static str = "0";
     const ORIGIN_HASH: &'static str = "1";
+    const DB_PATH: &'static str = "blockchain.db";
+
+    /// Starting difficulty, matching the previously hardcoded `"0000"` proof
+    /// suffix.
+    const INITIAL_DIFFICULTY: u32 = 4;
+    /// How often (in blocks) difficulty is retargeted.
+    const RETARGET_INTERVAL: u32 = 10;
+    /// Desired wall-clock time between blocks, in seconds.
+    const TARGET_BLOCK_SECONDS: i64 = 60;
+    /// Difficulty work can at most double or halve in a single retarget.
+    const MAX_ADJUSTMENT_FACTOR: f64 = 4.0;
+    /// A SHA-256 hex digest is 64 nibbles long, so a difficulty above this
+    /// could never be satisfied and would spin `proof_of_work` forever.
+    const MAX_DIFFICULTY: u32 = 63;
+
+    /// Fixed payout to whichever node mines a block, before mempool fees.
+    const BLOCK_REWARD: u64 = 1;
+    /// Maximum number of pending transactions included in a single block.
+    const MAX_BLOCK_TRANSACTIONS: usize = 100;
 
     pub fn new(node_manager: P2PNodeManager) -> Blockchain {
+        Blockchain::new_at(node_manager, Blockchain::DB_PATH)
+    }
+
+    fn new_at(node_manager: P2PNodeManager, db_path: &str) -> Blockchain {
+        let storage = Storage::open(db_path);
+        let chain = storage.load_chain();
+
+        let mut mempool = MemoryPool::new(OrderingStrategy::ByFee);
+        for transaction in storage.load_pending_transactions() {
+            // The arrival time of transactions loaded back from a previous
+            // run isn't recorded; 0 just puts them first among same-fee
+            // transactions under `OrderingStrategy::ByTimestamp`.
+            mempool.insert(transaction, 0);
+        }
+
+        // A block is needed to seed `last_block` before genesis exists; it's
+        // never read in that case, since `new_block` below passes an
+        // explicit `previous_hash` for the genesis block.
+        let last_block = chain
+            .last()
+            .cloned()
+            .unwrap_or_else(|| Block::from_parts(0, 0, Vec::new(), 0, String::new(), Blockchain::INITIAL_DIFFICULTY));
+
+        let confirmed_signatures = storage.load_confirmed_signatures();
+
         let mut blockchain = Blockchain {
-            chain: Vec::new(),
-            pending_transactions: Vec::new(),
-            node_manager
+            chain,
+            mempool,
+            node_manager,
+            storage,
+            last_block,
+            block_queue: BlockQueue::new(),
+            confirmed_signatures,
         };
 
-        // Create Genesis block
-        blockchain.new_block(100, Some(Blockchain::ORIGIN_HASH));
-        return blockchain;
+        if blockchain.chain.is_empty() {
+            blockchain
+                .new_block(100, Some(Blockchain::ORIGIN_HASH), Vec::new())
+                .expect("failed to persist genesis block");
+        }
+
+        blockchain
     }
 
-    pub fn mine(&mut self, node_uuid: &str) -> Block {
-        let last_proof = self.last_block().proof;
-        let proof = Blockchain::proof_of_work(last_proof);
+    pub fn mine(&mut self, node_uuid: &str) -> Result<Block, StorageError> {
+        let last_proof = self.last_block().proof();
+        let difficulty = Blockchain::expected_difficulty(&self.chain);
+        let proof = Blockchain::proof_of_work(last_proof, difficulty);
+
+        let selected = self.mempool.select(Blockchain::MAX_BLOCK_TRANSACTIONS);
+        let fees: u64 = selected.iter().map(|t| t.fee).sum();
 
-        // Pay the current node for mining
-        self.new_transaction(
+        // Pay the current node for mining: the fixed block reward plus every
+        // fee from the transactions it's mining. The coinbase transaction
+        // carries no signature, which recover_sender() allows for
+        // ORIGIN_SENDER, and it never enters the mempool itself.
+        let reward = UnverifiedTransaction::new(
             String::from(Blockchain::ORIGIN_SENDER),
             String::from(node_uuid),
-            1,
-        );
+            Blockchain::BLOCK_REWARD + fees,
+            0,
+            0,
+            String::new(),
+        )
+            .recover_sender()
+            .expect("coinbase transaction is always valid");
+
+        self.mempool.remove(&selected);
+
+        let mut transactions = selected;
+        transactions.push(reward);
 
-        self.new_block(proof, None)
+        self.new_block(proof, None, transactions)
     }
 
-    fn new_block(&mut self, proof: u64, previous_hash: Option<&str>) -> Block {
+    fn new_block(&mut self, proof: u64, previous_hash: Option<&str>, transactions: Vec<VerifiedTransaction>) -> Result<Block, StorageError> {
         let hash = previous_hash.map(|s| s.into()).unwrap_or_else(|| Blockchain::hash(self.last_block()));
-        let block = Block {
-            index: (self.chain.len() as u32) + 1,
-            timestamp: Utc::now().timestamp(),
-            transactions: self.pending_transactions.clone(),
+        let difficulty = Blockchain::expected_difficulty(&self.chain);
+        let block = Block::from_parts(
+            (self.chain.len() as u32) + 1,
+            Utc::now().timestamp(),
+            transactions,
             proof,
-            previous_hash: hash
-        };
+            hash,
+            difficulty,
+        );
 
-        // Clear transactions included in new block and push to chain
-        self.pending_transactions.clear();
+        self.storage.insert_block(&Blockchain::hash(&block), &block)?;
+        for t in block.transactions() {
+            if t.sender != Blockchain::ORIGIN_SENDER {
+                self.confirmed_signatures.insert(t.signature.clone());
+            }
+        }
         self.chain.push(block.clone());
+        self.last_block = block.clone();
+
+        Ok(block)
+    }
+
+    /// Recovers and checks the signer of `transaction`, rejecting it if the
+    /// recovered address doesn't match the declared `sender`, if it claims
+    /// to be from `ORIGIN_SENDER`, or if an identical transaction is already
+    /// pending or already confirmed on-chain (replay protection: `nonce`
+    /// only prevents a sender signing two transactions with the same nonce,
+    /// not the same signed payload being resubmitted after it's mined and
+    /// falls out of the mempool).
+    pub fn new_transaction(&mut self, transaction: UnverifiedTransaction) -> Result<u32, TransactionError> {
+        // `recover_sender` exempts ORIGIN_SENDER from signature verification
+        // so the internal coinbase built in `mine()` doesn't need to sign
+        // anything — that exemption must never be reachable from a request,
+        // or any client could mint currency by claiming to be the origin.
+        if transaction.sender == Blockchain::ORIGIN_SENDER {
+            return Err(TransactionError::ForgedCoinbase);
+        }
+
+        if self.mempool.contains(&transaction.signature) || self.confirmed_signatures.contains(&transaction.signature) {
+            return Err(TransactionError::DuplicateTransaction);
+        }
+
+        let verified = transaction.recover_sender()?;
+        self.storage.insert_pending_transaction(&verified)?;
+        self.mempool.insert(verified, Utc::now().timestamp());
+        Ok(self.chain.len() as u32)
+    }
+
+    pub fn mempool_info(&self) -> MemoryPoolInfo {
+        self.mempool.info()
+    }
 
-        block
+    /// Fetches candidate chains from every known peer and hands the ones
+    /// longer than ours to the `BlockQueue` for background verification.
+    /// Only needs a read lock: neither `get_chains()` nor `submit()` touch
+    /// `self.chain`, so Rocket's other routes stay unblocked while
+    /// verification runs on the queue's worker threads.
+    pub fn enqueue_conflicting_chains(&self) {
+        for chain in self.node_manager.get_chains() {
+            if chain.len() > self.chain.len() {
+                self.block_queue.submit(chain);
+            }
+        }
     }
 
-    pub fn new_transaction(&mut self, sender: String, recipient: String, amount: u64) -> u32 {
-        self.pending_transactions.push(Transaction::new(sender, recipient, amount));
-        self.chain.len() as u32
+    /// A cloneable handle onto the verification queue. Callers should hold
+    /// onto this (rather than re-fetching it per request) and wait on it
+    /// directly instead of calling back into `Blockchain`, since blocking on
+    /// verification while holding `Blockchain`'s `RwLock` would stall every
+    /// other route for as long as verification takes.
+    pub fn queue_handle(&self) -> BlockQueue {
+        self.block_queue.clone()
     }
 
-    pub fn resolve_conflicts(&mut self) -> bool {
-        // Get and verify the chain from all other nodes
-        let new_chain = self.node_manager
-            .get_chains()
+    /// Picks the longest verified candidate chain (if any is still longer
+    /// than ours), replaces the local chain with it, and re-queues for
+    /// mining any transaction that was only in the now-orphaned local
+    /// blocks. Returns the import route describing exactly what changed.
+    pub fn resolve_conflicts(&mut self) -> Result<ImportRoute, StorageError> {
+        let new_chain = self.block_queue
+            .drain_verified()
             .into_iter()
-            .find(|chain| {
-                chain.len() > self.chain.len() &&
-                    Blockchain::validate_chain(&chain)
-            });
+            .filter(|chain| chain.len() > self.chain.len())
+            .max_by_key(|chain| chain.len());
+
+        let c = match new_chain {
+            Some(c) => c,
+            None => return Ok(ImportRoute::none()),
+        };
 
-        if let Some(c) = new_chain {
-            self.chain = c.to_owned();
-            true
-        } else {
-            false
+        let (retracted, enacted, readded) = Blockchain::plan_reorg(&self.chain, &c);
+
+        let readded_at = Utc::now().timestamp();
+        for transaction in &readded {
+            self.storage.insert_pending_transaction(transaction)?;
+            self.mempool.insert(transaction.clone(), readded_at);
         }
+
+        let hashed_chain: Vec<(String, Block)> = c.iter()
+            .map(|block| (Blockchain::hash(block), block.clone()))
+            .collect();
+        self.storage.replace_chain(&hashed_chain)?;
+        self.last_block = c.last().expect("validated chain is non-empty").clone();
+        self.chain = c;
+        // A full reorg swaps out the confirmed set wholesale rather than
+        // patching it incrementally: retracted blocks' signatures must stop
+        // counting as confirmed (the readded ones above need to be eligible
+        // for re-mining), while enacted blocks' signatures now do.
+        self.confirmed_signatures = Blockchain::confirmed_signatures_of(&self.chain);
+
+        Ok(ImportRoute {
+            enacted: enacted.iter().map(Blockchain::hash).collect(),
+            retracted: retracted.iter().map(Blockchain::hash).collect(),
+            readded_transactions: readded.len(),
+        })
+    }
+
+    /// Finds the common ancestor between `local` and `incoming`, and
+    /// returns the local-only (retracted) blocks, the incoming-only
+    /// (enacted) blocks, and the retracted transactions that don't already
+    /// appear in an enacted block (and so need to go back into the
+    /// mempool). Coinbase rewards are never re-added: they belonged to the
+    /// orphaned block specifically, not to the transaction's sender.
+    fn plan_reorg(local: &[Block], incoming: &[Block]) -> (Vec<Block>, Vec<Block>, Vec<VerifiedTransaction>) {
+        let common = Blockchain::common_ancestor_len(local, incoming);
+        let retracted = local[common..].to_vec();
+        let enacted = incoming[common..].to_vec();
+
+        let enacted_signatures: HashSet<&str> = enacted.iter()
+            .flat_map(|block| block.transactions().iter().map(|t| t.signature.as_str()))
+            .collect();
+
+        let readded = retracted.iter()
+            .flat_map(|block| block.transactions().iter().cloned())
+            .filter(|t| t.sender != Blockchain::ORIGIN_SENDER)
+            .filter(|t| !enacted_signatures.contains(t.signature.as_str()))
+            .collect();
+
+        (retracted, enacted, readded)
+    }
+
+    fn common_ancestor_len(local: &[Block], incoming: &[Block]) -> usize {
+        local.iter().zip(incoming.iter())
+            .take_while(|&(a, b)| Blockchain::hash(a) == Blockchain::hash(b))
+            .count()
+    }
+
+    fn confirmed_signatures_of(chain: &[Block]) -> HashSet<String> {
+        chain.iter()
+            .flat_map(|block| block.transactions().iter())
+            .filter(|t| t.sender != Blockchain::ORIGIN_SENDER)
+            .map(|t| t.signature.clone())
+            .collect()
+    }
+
+    pub fn queue_info(&self) -> QueueInfo {
+        self.block_queue.info()
     }
 
     fn last_block(&mut self) -> &mut Block {
-        self.chain.last_mut().expect("Chain is empty of blocks.")
+        &mut self.last_block
     }
 
-    fn proof_of_work(last_proof: u64) -> u64 {
+    fn proof_of_work(last_proof: u64, difficulty: u32) -> u64 {
         let mut proof = 0;
-        while !(Blockchain::valid_proof(last_proof, proof)) {
+        while !(Blockchain::valid_proof(last_proof, proof, difficulty)) {
             proof += 1;
         }
         proof
     }
 
-    fn valid_proof(last_proof: u64, proof: u64) -> bool {
+    fn valid_proof(last_proof: u64, proof: u64, difficulty: u32) -> bool {
         let guess = format!("{}", last_proof * proof);
         let mut sha = Sha256::new();
         sha.input_str(&guess);
-        return sha.result_str().ends_with("0000");
+        let target = "0".repeat(difficulty as usize);
+        return sha.result_str().ends_with(&target);
+    }
+
+    /// The difficulty the *next* block built on top of `prefix` must have.
+    /// Unchanged between retargets; every `RETARGET_INTERVAL` blocks it's
+    /// adjusted based on how long that window actually took to mine versus
+    /// `TARGET_BLOCK_SECONDS`, clamped to `MAX_ADJUSTMENT_FACTOR`.
+    fn expected_difficulty(prefix: &[Block]) -> u32 {
+        let height = prefix.len() as u32;
+
+        if height == 0 {
+            return Blockchain::INITIAL_DIFFICULTY;
+        }
+
+        let current_difficulty = prefix.last().unwrap().difficulty();
+
+        let window = Blockchain::RETARGET_INTERVAL as usize;
+        if height % Blockchain::RETARGET_INTERVAL != 0 || prefix.len() < window {
+            return current_difficulty;
+        }
+
+        let elapsed = prefix[prefix.len() - 1].timestamp() - prefix[prefix.len() - window].timestamp();
+        let target = Blockchain::TARGET_BLOCK_SECONDS * (window as i64 - 1);
+
+        Blockchain::retarget_difficulty(current_difficulty, elapsed, target)
+    }
+
+    /// Scales the work represented by `current_difficulty` (`16^difficulty`
+    /// hash attempts) by `target / elapsed`, clamped to
+    /// `MAX_ADJUSTMENT_FACTOR`, and converts the result back to a nibble
+    /// count, itself clamped to `MAX_DIFFICULTY` so a sustained run of
+    /// faster-than-target blocks can never retarget past what a SHA-256
+    /// digest could satisfy.
+    fn retarget_difficulty(current_difficulty: u32, elapsed_seconds: i64, target_seconds: i64) -> u32 {
+        let elapsed = cmp::max(1, elapsed_seconds) as f64;
+        let target = cmp::max(1, target_seconds) as f64;
+        let ratio = (target / elapsed)
+            .max(1.0 / Blockchain::MAX_ADJUSTMENT_FACTOR)
+            .min(Blockchain::MAX_ADJUSTMENT_FACTOR);
+
+        let current_work = 16f64.powi(current_difficulty as i32);
+        let target_work = (current_work * ratio).max(1.0);
+
+        let difficulty = (target_work.ln() / 16f64.ln()).round() as u32;
+        cmp::min(Blockchain::MAX_DIFFICULTY, cmp::max(1, difficulty))
     }
 
     fn hash(block: &Block) -> String {
@@ -137,11 +581,54 @@ impl Blockchain {
         return sha.result_str();
     }
 
-    fn validate_chain(chain: &Vec<Block>) -> bool {
-        chain.iter().zip(&chain[1..]).all(|(a, b)| -> bool {
-            Blockchain::hash(a) == b.previous_hash &&
-                Blockchain::valid_proof(b.proof, a.proof)
-        })
+    /// The canonical payload signed by a transaction's sender: the bincode
+    /// serialization of `(recipient, amount, nonce, fee)`, hashed with
+    /// SHA-256.
+    fn signing_digest(recipient: &str, amount: u64, nonce: u64, fee: u64) -> [u8; 32] {
+        let payload = serialize(&(recipient, amount, nonce, fee), Infinite).unwrap();
+        let mut sha = Sha256::new();
+        sha.input(&payload);
+        let mut digest = [0u8; 32];
+        sha.result(&mut digest);
+        digest
+    }
+
+    pub(crate) fn validate_chain(chain: &Vec<Block>) -> bool {
+        let links_and_difficulty_valid = (0..chain.len()).all(|i| {
+            let block = &chain[i];
+            let expected_difficulty = Blockchain::expected_difficulty(&chain[..i]);
+
+            if block.difficulty != expected_difficulty {
+                return false;
+            }
+
+            if i == 0 {
+                return true;
+            }
+
+            let previous = &chain[i - 1];
+            Blockchain::hash(previous) == block.previous_hash &&
+                Blockchain::valid_proof(previous.proof, block.proof, expected_difficulty)
+        });
+
+        let transactions_valid = chain.iter().all(|block| {
+            block.transactions.iter().all(Blockchain::verify_stored_transaction)
+        });
+
+        // Replay protection for the chain itself: the same non-coinbase
+        // signature must not be confirmed twice, whether that's within one
+        // block or spread across the candidate chain.
+        let mut seen_signatures = HashSet::new();
+        let no_duplicate_transactions = chain.iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|t| t.sender != Blockchain::ORIGIN_SENDER)
+            .all(|t| seen_signatures.insert(t.signature.as_str()));
+
+        links_and_difficulty_valid && transactions_valid && no_duplicate_transactions
+    }
+
+    fn verify_stored_transaction(transaction: &VerifiedTransaction) -> bool {
+        transaction.clone().into_unverified().recover_sender().is_ok()
     }
 }
 
@@ -150,13 +637,42 @@ impl Blockchain {
 mod tests {
     use super::*;
     use core::nodemanager::Node;
+    use secp256k1::key::SecretKey;
+
+    fn test_node_manager() -> P2PNodeManager {
+        P2PNodeManager::new(Node::new("127.0.0.1:8000".to_owned()))
+    }
+
+    // Each test gets its own private `:memory:` SQLite connection, so tests
+    // never see another test's persisted chain.
+    fn test_blockchain() -> Blockchain {
+        Blockchain::new_at(test_node_manager(), ":memory:")
+    }
+
+    fn test_keypair() -> (Secp256k1, SecretKey, PublicKey) {
+        let secp = Secp256k1::new();
+        let secret = SecretKey::from_slice(&secp, &[7u8; 32]).unwrap();
+        let public = PublicKey::from_secret_key(&secp, &secret).unwrap();
+        (secp, secret, public)
+    }
+
+    fn sign(secp: &Secp256k1, secret: &SecretKey, recipient: &str, amount: u64, nonce: u64, fee: u64) -> String {
+        let digest = Blockchain::signing_digest(recipient, amount, nonce, fee);
+        let message = Message::from_slice(&digest).unwrap();
+        let recoverable_sig = secp.sign_recoverable(&message, secret).unwrap();
+        let (recovery_id, sig_bytes) = recoverable_sig.serialize_compact(secp);
+
+        let mut full = vec![recovery_id.to_i32() as u8];
+        full.extend_from_slice(&sig_bytes);
+        to_hex(&full)
+    }
 
     #[test]
     fn genesis_blockchain() {
-        let blockchain = Blockchain::new(NODE_MANAGER);
+        let blockchain = test_blockchain();
 
         assert_eq!(blockchain.chain.len() as u32, 1);
-        assert_eq!(blockchain.pending_transactions.len() as u32, 0);
+        assert_eq!(blockchain.mempool.info().transactions_count, 0);
     }
 
     #[test]
@@ -167,6 +683,7 @@ mod tests {
             transactions: Vec::new(),
             proof: 100,
             previous_hash: "1".to_owned(),
+            difficulty: Blockchain::INITIAL_DIFFICULTY,
         };
 
         let block2 = block.clone();
@@ -182,11 +699,22 @@ mod tests {
             transactions: Vec::new(),
             proof: 100,
             previous_hash: "1".to_owned(),
+            difficulty: Blockchain::INITIAL_DIFFICULTY,
         };
 
         let h1 = Blockchain::hash(&block);
 
-        block.transactions.push(Transaction::new("alice".to_owned(), "bob".to_owned(), 10));
+        let (secp, secret, public) = test_keypair();
+        let sender = address_from_pubkey(&public);
+        let signature = sign(&secp, &secret, "bob", 10, 0, 0);
+        block.transactions.push(VerifiedTransaction {
+            sender,
+            recipient: "bob".to_owned(),
+            amount: 10,
+            nonce: 0,
+            fee: 0,
+            signature,
+        });
 
         let h2 = Blockchain::hash(&block);
 
@@ -196,29 +724,141 @@ mod tests {
     #[test]
     fn validate_proof() {
         let last_proof = 1;
-        let valid = 31214; // from: Blockchain::proof_of_work(last_proof);
+        let valid = 31214; // from: Blockchain::proof_of_work(last_proof, Blockchain::INITIAL_DIFFICULTY);
+
+        assert!(Blockchain::valid_proof(last_proof, valid, Blockchain::INITIAL_DIFFICULTY));
+        assert!(!Blockchain::valid_proof(last_proof, valid - 1, Blockchain::INITIAL_DIFFICULTY));
+    }
+
+    #[test]
+    fn recover_sender_accepts_valid_signature() {
+        let (secp, secret, public) = test_keypair();
+        let sender = address_from_pubkey(&public);
+        let signature = sign(&secp, &secret, "bob", 10, 0, 0);
+        let unverified = UnverifiedTransaction::new(sender.clone(), "bob".to_owned(), 10, 0, 0, signature);
+
+        let verified = unverified.recover_sender().expect("signature should verify");
+        assert_eq!(verified.sender, sender);
+    }
 
-        assert!(Blockchain::valid_proof(last_proof, valid));
-        assert!(!Blockchain::valid_proof(last_proof, valid - 1));
+    #[test]
+    fn recover_sender_rejects_forged_sender() {
+        let (secp, secret, _public) = test_keypair();
+        let signature = sign(&secp, &secret, "bob", 10, 0, 0);
+        let forged = UnverifiedTransaction::new("someone-else".to_owned(), "bob".to_owned(), 10, 0, 0, signature);
+
+        assert!(forged.recover_sender().is_err());
+    }
+
+    #[test]
+    fn recover_sender_exempts_coinbase() {
+        let coinbase = UnverifiedTransaction::new(
+            Blockchain::ORIGIN_SENDER.to_owned(),
+            "bob".to_owned(),
+            1,
+            0,
+            0,
+            String::new(),
+        );
+        assert!(coinbase.recover_sender().is_ok());
     }
 
     #[test]
     fn validate_new_transaction() {
-        let mut blockchain = Blockchain::new(NODE_MANAGER);
-        blockchain.new_transaction("alice".to_owned(), "bob".to_owned(), 10);
+        let mut blockchain = test_blockchain();
+        let (secp, secret, public) = test_keypair();
+        let sender = address_from_pubkey(&public);
+        let signature = sign(&secp, &secret, "bob", 10, 0, 0);
+
+        blockchain
+            .new_transaction(UnverifiedTransaction::new(sender, "bob".to_owned(), 10, 0, 0, signature))
+            .expect("signed transaction should be accepted");
 
-        assert_eq!(blockchain.pending_transactions.len(), 1, "New transaction should be added to pending.")
+        assert_eq!(blockchain.mempool.info().transactions_count, 1, "New transaction should be added to pending.")
+    }
+
+    #[test]
+    fn new_transaction_rejects_duplicates() {
+        let mut blockchain = test_blockchain();
+        let (secp, secret, public) = test_keypair();
+        let sender = address_from_pubkey(&public);
+        let signature = sign(&secp, &secret, "bob", 10, 0, 0);
+
+        blockchain
+            .new_transaction(UnverifiedTransaction::new(sender.clone(), "bob".to_owned(), 10, 0, 0, signature.clone()))
+            .expect("first submission should be accepted");
+
+        let result = blockchain.new_transaction(UnverifiedTransaction::new(sender, "bob".to_owned(), 10, 0, 0, signature));
+
+        match result {
+            Err(TransactionError::DuplicateTransaction) => (),
+            _ => panic!("resubmitting the same transaction should be rejected as a duplicate"),
+        }
+    }
+
+    #[test]
+    fn new_transaction_rejects_forged_coinbase() {
+        let mut blockchain = test_blockchain();
+
+        let result = blockchain.new_transaction(UnverifiedTransaction::new(
+            Blockchain::ORIGIN_SENDER.to_owned(),
+            "attacker".to_owned(),
+            999_999,
+            0,
+            0,
+            String::new(),
+        ));
+
+        match result {
+            Err(TransactionError::ForgedCoinbase) => (),
+            _ => panic!("a request claiming to be from ORIGIN_SENDER should be rejected"),
+        }
     }
 
     #[test]
     fn validate_new_block() {
-        let mut blockchain = Blockchain::new(NODE_MANAGER);
-        blockchain.new_transaction("alice".to_owned(), "bob".to_owned(), 10);
-        // Generate a block
-        let new_block = blockchain.new_block(100, Some(&"1".to_owned()));
+        let mut blockchain = test_blockchain();
+        let (secp, secret, public) = test_keypair();
+        let sender = address_from_pubkey(&public);
+        let signature = sign(&secp, &secret, "bob", 10, 0, 0);
+
+        blockchain
+            .new_transaction(UnverifiedTransaction::new(sender, "bob".to_owned(), 10, 0, 0, signature))
+            .expect("signed transaction should be accepted");
+
+        let selected = blockchain.mempool.select(Blockchain::MAX_BLOCK_TRANSACTIONS);
+        blockchain.mempool.remove(&selected);
+        let new_block = blockchain
+            .new_block(100, Some(&"1".to_owned()), selected)
+            .expect("block should persist");
 
         assert_eq!(new_block.transactions.len(), 1, "New returned block should contain transaction.");
-        assert_eq!(blockchain.pending_transactions.len(), 0, "Blockchain should be empty after new block generation.")
+        assert_eq!(blockchain.mempool.info().transactions_count, 0, "Mempool should be empty after the transaction is mined.")
+    }
+
+    #[test]
+    fn mine_selects_highest_fee_transactions_first() {
+        let mut blockchain = test_blockchain();
+        let (secp, secret, public) = test_keypair();
+        let sender = address_from_pubkey(&public);
+
+        let cheap_signature = sign(&secp, &secret, "bob", 10, 0, 1);
+        blockchain
+            .new_transaction(UnverifiedTransaction::new(sender.clone(), "bob".to_owned(), 10, 0, 1, cheap_signature))
+            .expect("cheap transaction should be accepted");
+
+        let pricey_signature = sign(&secp, &secret, "carol", 10, 1, 50);
+        blockchain
+            .new_transaction(UnverifiedTransaction::new(sender, "carol".to_owned(), 10, 1, 50, pricey_signature))
+            .expect("pricey transaction should be accepted");
+
+        let block = blockchain.mine("miner").expect("block should persist");
+
+        assert_eq!(block.transactions.len(), 3, "Both pending transactions plus the coinbase should be mined.");
+        assert_eq!(block.transactions[0].recipient, "carol", "The higher-fee transaction should be selected first.");
+
+        let coinbase = block.transactions.last().unwrap();
+        assert_eq!(coinbase.amount, Blockchain::BLOCK_REWARD + 51, "Coinbase should collect the block reward plus both fees.");
     }
 
     #[test]
@@ -226,11 +866,9 @@ mod tests {
         let node_uuid = "1";
 
         // Valid chain check
-        let mut blockchain = Blockchain::new(NODE_MANAGER);
-        blockchain.new_transaction("alice".to_owned(), "bob".to_owned(), 10);
-        blockchain.mine(node_uuid);
-        blockchain.new_transaction("alice".to_owned(), "bob".to_owned(), 15);
-        blockchain.mine(node_uuid);
+        let mut blockchain = test_blockchain();
+        blockchain.mine(node_uuid).expect("block should persist");
+        blockchain.mine(node_uuid).expect("block should persist");
         assert!(Blockchain::validate_chain(&blockchain.chain), "Chain should be valid.");
 
         // Invalid proof chain check
@@ -243,4 +881,105 @@ mod tests {
         invalid_hash_chain[1].previous_hash = "invalidhash".to_owned();
         assert!(!Blockchain::validate_chain(&invalid_hash_chain), "Should not invalidate incorrect hash chain.")
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn validate_chain_rejects_replayed_transaction() {
+        let node_uuid = "1";
+        let mut blockchain = test_blockchain();
+        let (secp, secret, public) = test_keypair();
+        let sender = address_from_pubkey(&public);
+        let signature = sign(&secp, &secret, "bob", 10, 0, 0);
+
+        blockchain
+            .new_transaction(UnverifiedTransaction::new(sender, "bob".to_owned(), 10, 0, 0, signature.clone()))
+            .expect("signed transaction should be accepted");
+        blockchain.mine(node_uuid).expect("block should persist");
+
+        let mut replayed_chain = blockchain.chain.to_vec();
+        let mined = replayed_chain[1].transactions[0].clone();
+        replayed_chain[0].transactions.push(mined);
+
+        assert!(
+            !Blockchain::validate_chain(&replayed_chain),
+            "Chain containing the same signature twice should not validate."
+        );
+    }
+
+    fn test_transaction(sender: &str, recipient: &str, amount: u64, nonce: u64) -> VerifiedTransaction {
+        VerifiedTransaction {
+            sender: sender.to_owned(),
+            recipient: recipient.to_owned(),
+            amount,
+            nonce,
+            fee: 0,
+            signature: format!("{}-{}-{}-{}", sender, recipient, amount, nonce),
+        }
+    }
+
+    #[test]
+    fn plan_reorg_readds_orphaned_transactions_not_in_new_chain() {
+        let genesis = Block::from_parts(1, 0, Vec::new(), 100, "1".to_owned(), Blockchain::INITIAL_DIFFICULTY);
+
+        let orphaned_tx = test_transaction("alice", "bob", 10, 0);
+        let local_tip = Block::from_parts(
+            2,
+            1,
+            vec![orphaned_tx.clone()],
+            200,
+            Blockchain::hash(&genesis),
+            Blockchain::INITIAL_DIFFICULTY,
+        );
+        let local = vec![genesis.clone(), local_tip];
+
+        // The peer's chain also mined `orphaned_tx`, so it shouldn't be
+        // re-added, but it additionally carries a transaction the local
+        // chain never saw.
+        let fresh_tx = test_transaction("carol", "dave", 5, 0);
+        let incoming_tip = Block::from_parts(
+            2,
+            1,
+            vec![orphaned_tx.clone(), fresh_tx],
+            300,
+            Blockchain::hash(&genesis),
+            Blockchain::INITIAL_DIFFICULTY,
+        );
+        let incoming = vec![genesis, incoming_tip];
+
+        let (retracted, enacted, readded) = Blockchain::plan_reorg(&local, &incoming);
+
+        assert_eq!(retracted.len(), 1, "Only the local-only tip should be retracted.");
+        assert_eq!(enacted.len(), 1, "Only the incoming-only tip should be enacted.");
+        assert!(readded.is_empty(), "The orphaned transaction already exists in the enacted chain.");
+    }
+
+    #[test]
+    fn plan_reorg_drops_coinbase_rewards() {
+        let genesis = Block::from_parts(1, 0, Vec::new(), 100, "1".to_owned(), Blockchain::INITIAL_DIFFICULTY);
+
+        let coinbase = test_transaction(Blockchain::ORIGIN_SENDER, "miner-a", 1, 0);
+        let local_tip = Block::from_parts(
+            2,
+            1,
+            vec![coinbase],
+            200,
+            Blockchain::hash(&genesis),
+            Blockchain::INITIAL_DIFFICULTY,
+        );
+        let local = vec![genesis.clone(), local_tip];
+
+        let other_coinbase = test_transaction(Blockchain::ORIGIN_SENDER, "miner-b", 1, 0);
+        let incoming_tip = Block::from_parts(
+            2,
+            1,
+            vec![other_coinbase],
+            300,
+            Blockchain::hash(&genesis),
+            Blockchain::INITIAL_DIFFICULTY,
+        );
+        let incoming = vec![genesis, incoming_tip];
+
+        let (_, _, readded) = Blockchain::plan_reorg(&local, &incoming);
+
+        assert!(readded.is_empty(), "Coinbase rewards belong to the orphaned block, not the mempool.");
+    }
+}
@@ -0,0 +1,79 @@
+use core::blockchain::VerifiedTransaction;
+
+/// How `MemoryPool::select` orders pending transactions when it picks which
+/// ones go into the next block.
+pub enum OrderingStrategy {
+    /// Highest fee first; ties broken by whichever arrived earlier.
+    ByFee,
+    /// Plain FIFO, i.e. the order transactions were received in.
+    ByTimestamp,
+}
+
+struct PooledTransaction {
+    transaction: VerifiedTransaction,
+    received_at: i64,
+}
+
+/// A point-in-time snapshot of the mempool, exposed through `/mempool`.
+#[derive(Serialize, Debug, Clone)]
+pub struct MemoryPoolInfo {
+    pub transactions_count: usize,
+    pub total_fees: u64,
+}
+
+/// Holds transactions that have been accepted but not yet mined, ordered by
+/// `strategy` so `select` can hand `Blockchain::mine` the most valuable ones
+/// first instead of draining the pool in arrival order.
+pub struct MemoryPool {
+    transactions: Vec<PooledTransaction>,
+    strategy: OrderingStrategy,
+}
+
+impl MemoryPool {
+    pub fn new(strategy: OrderingStrategy) -> MemoryPool {
+        MemoryPool {
+            transactions: Vec::new(),
+            strategy,
+        }
+    }
+
+    pub fn contains(&self, signature: &str) -> bool {
+        self.transactions.iter().any(|p| p.transaction.signature == signature)
+    }
+
+    pub fn insert(&mut self, transaction: VerifiedTransaction, received_at: i64) {
+        self.transactions.push(PooledTransaction { transaction, received_at });
+    }
+
+    /// The top `limit` transactions in `strategy` order, without removing
+    /// them from the pool.
+    pub fn select(&self, limit: usize) -> Vec<VerifiedTransaction> {
+        let mut ordered: Vec<&PooledTransaction> = self.transactions.iter().collect();
+
+        match self.strategy {
+            OrderingStrategy::ByFee => {
+                ordered.sort_by(|a, b| {
+                    b.transaction.fee.cmp(&a.transaction.fee).then(a.received_at.cmp(&b.received_at))
+                });
+            }
+            OrderingStrategy::ByTimestamp => {
+                ordered.sort_by_key(|p| p.received_at);
+            }
+        }
+
+        ordered.into_iter().take(limit).map(|p| p.transaction.clone()).collect()
+    }
+
+    /// Drops `mined` (by signature) from the pool, leaving everything else
+    /// behind for the next block.
+    pub fn remove(&mut self, mined: &[VerifiedTransaction]) {
+        self.transactions.retain(|p| !mined.iter().any(|t| t.signature == p.transaction.signature));
+    }
+
+    pub fn info(&self) -> MemoryPoolInfo {
+        MemoryPoolInfo {
+            transactions_count: self.transactions.len(),
+            total_fees: self.transactions.iter().map(|p| p.transaction.fee).sum(),
+        }
+    }
+}
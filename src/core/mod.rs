@@ -0,0 +1,5 @@
+pub mod blockchain;
+pub mod mempool;
+pub mod nodemanager;
+pub mod queue;
+pub mod storage;
@@ -0,0 +1,121 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use core::blockchain::{Blockchain, Chain};
+
+/// A point-in-time snapshot of `BlockQueue`'s backlog, exposed through
+/// `/queue/status` so operators can see whether verification is keeping up
+/// with incoming peer chains.
+#[derive(Serialize, Debug, Clone)]
+pub struct QueueInfo {
+    pub unverified: usize,
+    pub verifying: usize,
+    pub verified: usize,
+}
+
+struct QueueState {
+    unverified: VecDeque<Chain>,
+    verifying: usize,
+    verified: VecDeque<Chain>,
+}
+
+/// Decouples chain verification (hashing + proof-of-work checks) from the
+/// request thread. Candidate chains fetched from peers are pushed onto an
+/// unverified queue; a fixed pool of worker threads pops them, verifies them
+/// with `Blockchain::validate_chain`, and pushes survivors onto a verified
+/// queue for `Blockchain::resolve_conflicts` to consume under its own lock.
+///
+/// Cheaply `Clone`-able: every field is an `Arc`, so a clone shares the same
+/// underlying queue and workers. `Blockchain::queue_handle` hands one out so
+/// callers can wait on verification without holding `Blockchain`'s own lock.
+#[derive(Clone)]
+pub struct BlockQueue {
+    state: Arc<Mutex<QueueState>>,
+    work_available: Arc<Condvar>,
+    drained: Arc<Condvar>,
+}
+
+impl BlockQueue {
+    pub fn new() -> BlockQueue {
+        let worker_count = cmp::max(1, num_cpus::get().saturating_sub(2));
+
+        let queue = BlockQueue {
+            state: Arc::new(Mutex::new(QueueState {
+                unverified: VecDeque::new(),
+                verifying: 0,
+                verified: VecDeque::new(),
+            })),
+            work_available: Arc::new(Condvar::new()),
+            drained: Arc::new(Condvar::new()),
+        };
+
+        for _ in 0..worker_count {
+            let state = queue.state.clone();
+            let work_available = queue.work_available.clone();
+            let drained = queue.drained.clone();
+            thread::spawn(move || BlockQueue::worker_loop(state, work_available, drained));
+        }
+
+        queue
+    }
+
+    /// Enqueues a candidate chain for background verification.
+    pub fn submit(&self, chain: Chain) {
+        let mut state = self.state.lock().unwrap();
+        state.unverified.push_back(chain);
+        self.work_available.notify_one();
+    }
+
+    /// Removes and returns every chain that has passed verification so far.
+    pub fn drain_verified(&self) -> Vec<Chain> {
+        let mut state = self.state.lock().unwrap();
+        state.verified.drain(..).collect()
+    }
+
+    /// Blocks until every submitted chain has either been verified or
+    /// rejected, i.e. the unverified queue is empty and no worker is still
+    /// verifying one.
+    pub fn wait_until_drained(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.unverified.is_empty() || state.verifying > 0 {
+            state = self.drained.wait(state).unwrap();
+        }
+    }
+
+    pub fn info(&self) -> QueueInfo {
+        let state = self.state.lock().unwrap();
+        QueueInfo {
+            unverified: state.unverified.len(),
+            verifying: state.verifying,
+            verified: state.verified.len(),
+        }
+    }
+
+    fn worker_loop(state: Arc<Mutex<QueueState>>, work_available: Arc<Condvar>, drained: Arc<Condvar>) {
+        loop {
+            let chain = {
+                let mut guard = state.lock().unwrap();
+                while guard.unverified.is_empty() {
+                    guard = work_available.wait(guard).unwrap();
+                }
+                let chain = guard.unverified.pop_front().unwrap();
+                guard.verifying += 1;
+                chain
+            };
+
+            let is_valid = Blockchain::validate_chain(&chain);
+
+            let mut guard = state.lock().unwrap();
+            guard.verifying -= 1;
+            if is_valid {
+                guard.verified.push_back(chain);
+            }
+
+            if guard.unverified.is_empty() && guard.verifying == 0 {
+                drained.notify_all();
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use rusqlite::{Connection, Row, NO_PARAMS};
+
+use core::blockchain::{Block, VerifiedTransaction};
+
+/// SQLite-backed durability for the chain and mempool. `Blockchain` writes
+/// through to a `Storage` on every `new_block`/`new_transaction` so a
+/// restart can reload the chain instead of rebuilding it from genesis.
+pub struct Storage {
+    conn: Connection,
+    last_block_hash: Option<String>,
+}
+
+/// A write reachable from a request failed to persist. Returned instead of
+/// panicking, since these writes run under `Blockchain`'s write lock and a
+/// panic there would poison it, bricking every route for the rest of the
+/// process's life.
+#[derive(Debug)]
+pub struct StorageError(rusqlite::Error);
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> StorageError {
+        StorageError(err)
+    }
+}
+
+impl Storage {
+    pub fn open(path: &str) -> Storage {
+        let conn = Connection::open(path).expect("failed to open blockchain.db");
+        Storage::create_tables(&conn);
+        let last_block_hash = Storage::query_last_block_hash(&conn);
+
+        Storage {
+            conn,
+            last_block_hash,
+        }
+    }
+
+    fn create_tables(conn: &Connection) {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS blocks (
+                hash TEXT PRIMARY KEY,
+                idx INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                proof INTEGER NOT NULL,
+                previous_hash TEXT NOT NULL,
+                difficulty INTEGER NOT NULL
+            )",
+            NO_PARAMS,
+        ).expect("failed to create blocks table");
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS transactions (
+                block_hash TEXT,
+                sender TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                nonce INTEGER NOT NULL,
+                fee INTEGER NOT NULL,
+                signature TEXT NOT NULL,
+                FOREIGN KEY(block_hash) REFERENCES blocks(hash)
+            )",
+            NO_PARAMS,
+        ).expect("failed to create transactions table");
+    }
+
+    fn query_last_block_hash(conn: &Connection) -> Option<String> {
+        conn.query_row(
+            "SELECT hash FROM blocks ORDER BY idx DESC LIMIT 1",
+            NO_PARAMS,
+            |row| row.get(0),
+        ).ok()
+    }
+
+    /// Loads every stored block (with its transactions) in chain order.
+    pub fn load_chain(&self) -> Vec<Block> {
+        let mut stmt = self.conn
+            .prepare("SELECT hash, idx, timestamp, proof, previous_hash, difficulty FROM blocks ORDER BY idx ASC")
+            .expect("failed to prepare block query");
+
+        let rows = stmt
+            .query_map(NO_PARAMS, |row| {
+                let hash: String = row.get(0);
+                (hash, row.get(1), row.get(2), row.get(3), row.get(4), row.get::<_, i64>(5) as u32)
+            })
+            .expect("failed to query blocks")
+            .filter_map(Result::ok)
+            .collect::<Vec<(String, u32, i64, u64, String, u32)>>();
+
+        rows.into_iter()
+            .map(|(hash, index, timestamp, proof, previous_hash, difficulty)| {
+                let transactions = self.load_transactions(Some(&hash));
+                Block::from_parts(index, timestamp, transactions, proof, previous_hash, difficulty)
+            })
+            .collect()
+    }
+
+    /// Loads the mempool left over from a previous run (transactions that
+    /// were accepted but never made it into a mined block).
+    pub fn load_pending_transactions(&self) -> Vec<VerifiedTransaction> {
+        self.load_transactions(None)
+    }
+
+    /// Every signature that has already been mined into a block, for replay
+    /// protection: a transaction carrying one of these must never be
+    /// accepted or re-validated again. Coinbase rewards share a single
+    /// empty signature across every block and are excluded, since they're
+    /// already exempted from signature verification entirely.
+    pub fn load_confirmed_signatures(&self) -> HashSet<String> {
+        let mut stmt = self.conn
+            .prepare("SELECT signature FROM transactions WHERE block_hash IS NOT NULL AND signature != ''")
+            .expect("failed to prepare confirmed signature query");
+
+        stmt.query_map(NO_PARAMS, |row| row.get(0))
+            .expect("failed to query confirmed signatures")
+            .filter_map(Result::ok)
+            .collect()
+    }
+
+    fn load_transactions(&self, block_hash: Option<&str>) -> Vec<VerifiedTransaction> {
+        let sql = match block_hash {
+            Some(_) => "SELECT sender, recipient, amount, nonce, fee, signature FROM transactions WHERE block_hash = ?1",
+            None => "SELECT sender, recipient, amount, nonce, fee, signature FROM transactions WHERE block_hash IS NULL",
+        };
+        let mut stmt = self.conn.prepare(sql).expect("failed to prepare transaction query");
+
+        let rows = match block_hash {
+            Some(hash) => stmt.query_map(&[&hash], Storage::row_to_transaction),
+            None => stmt.query_map(NO_PARAMS, Storage::row_to_transaction),
+        };
+
+        rows.expect("failed to query transactions")
+            .filter_map(Result::ok)
+            .collect()
+    }
+
+    fn row_to_transaction(row: &Row) -> VerifiedTransaction {
+        VerifiedTransaction {
+            sender: row.get(0),
+            recipient: row.get(1),
+            amount: row.get::<_, i64>(2) as u64,
+            nonce: row.get::<_, i64>(3) as u64,
+            fee: row.get::<_, i64>(4) as u64,
+            signature: row.get(5),
+        }
+    }
+
+    /// Whether a block with this hash has already been persisted.
+    pub fn contains_block(&self, hash: &str) -> bool {
+        if self.last_block_hash.as_ref().map(|h| h == hash).unwrap_or(false) {
+            return true;
+        }
+
+        Storage::block_exists(&self.conn, hash)
+    }
+
+    fn block_exists(conn: &Connection, hash: &str) -> bool {
+        conn.query_row("SELECT 1 FROM blocks WHERE hash = ?1", &[&hash], |_row| true)
+            .unwrap_or(false)
+    }
+
+    /// Write-through for a single newly mined block, plus clearing whichever
+    /// of its transactions were previously sitting in the pending pool
+    /// (the coinbase reward never was, so it's simply not found).
+    pub fn insert_block(&mut self, hash: &str, block: &Block) -> Result<(), StorageError> {
+        Storage::insert_block_with(&self.conn, hash, block)?;
+        for t in block.transactions() {
+            self.conn.execute(
+                "DELETE FROM transactions WHERE block_hash IS NULL AND signature = ?1",
+                &[&t.signature],
+            )?;
+        }
+        self.last_block_hash = Some(hash.to_owned());
+        Ok(())
+    }
+
+    fn insert_block_with(conn: &Connection, hash: &str, block: &Block) -> Result<(), StorageError> {
+        conn.execute(
+            "INSERT OR IGNORE INTO blocks (hash, idx, timestamp, proof, previous_hash, difficulty)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            &[
+                &hash,
+                &(block.index() as i64),
+                &block.timestamp(),
+                &(block.proof() as i64),
+                &block.previous_hash(),
+                &(block.difficulty() as i64),
+            ],
+        )?;
+
+        for t in block.transactions() {
+            conn.execute(
+                "INSERT INTO transactions (block_hash, sender, recipient, amount, nonce, fee, signature)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                &[&hash, &t.sender, &t.recipient, &(t.amount as i64), &(t.nonce as i64), &(t.fee as i64), &t.signature],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn insert_pending_transaction(&self, t: &VerifiedTransaction) -> Result<(), StorageError> {
+        self.conn.execute(
+            "INSERT INTO transactions (block_hash, sender, recipient, amount, nonce, fee, signature)
+             VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6)",
+            &[&t.sender, &t.recipient, &(t.amount as i64), &(t.nonce as i64), &(t.fee as i64), &t.signature],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the stored chain with `chain`, skipping blocks already on
+    /// disk. Runs inside a single DB transaction so a consensus swap that
+    /// fails partway can't leave the store in a mixed old/new state.
+    pub fn replace_chain(&mut self, chain: &[(String, Block)]) -> Result<(), StorageError> {
+        let tx = self.conn.transaction()?;
+
+        for (hash, block) in chain {
+            if !Storage::block_exists(&tx, hash) {
+                Storage::insert_block_with(&tx, hash, block)?;
+            }
+        }
+
+        tx.commit()?;
+        self.last_block_hash = chain.last().map(|(hash, _)| hash.clone());
+        Ok(())
+    }
+}
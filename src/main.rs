@@ -13,10 +13,14 @@ extern crate log;
 extern crate bincode;
 extern crate crypto;
 extern crate chrono;
+extern crate num_cpus;
 extern crate pnet;
+extern crate rusqlite;
+extern crate secp256k1;
 extern crate uuid;
 
 use rocket::State;
+use rocket::http::Status;
 use rocket::response::status;
 use rocket_contrib::{Json, Value};
 use uuid::Uuid;
@@ -27,12 +31,15 @@ use std::sync::RwLock;
 
 mod core;
 
-use core::blockchain::{Block, Blockchain, Chain, Transaction};
+use core::blockchain::{Block, Blockchain, Chain, TransactionError, UnverifiedTransaction};
+use core::mempool::MemoryPoolInfo;
 use core::nodemanager::{Node, P2PNodeManager};
+use core::queue::{BlockQueue, QueueInfo};
 
 struct Application {
     node_identifier: String,
     blockchain: RwLock<Blockchain>,
+    block_queue: BlockQueue,
 }
 
 fn main() {
@@ -45,15 +52,19 @@ fn main() {
 
     let node_manager = P2PNodeManager::new(local);
 
+    let blockchain = Blockchain::new(node_manager);
+    let block_queue = blockchain.queue_handle();
+
     let app = Application {
         node_identifier: node_id,
-        blockchain: RwLock::new(Blockchain::new(node_manager)),
+        blockchain: RwLock::new(blockchain),
+        block_queue,
     };
 
     rocket::ignite()
         .mount(
             "/",
-            routes![chain, nodes, node_info, node_consensus, node_register, mine, new_transaction],
+            routes![chain, nodes, node_info, node_consensus, node_register, mine, new_transaction, queue_status, mempool_status],
         )
         .manage(app)
         .launch();
@@ -85,51 +96,79 @@ fn node_register(state: State<Application>, node: Json<Node>) -> status::Created
 }
 
 #[post("/node/resolve", format = "application/json")]
-fn node_consensus(state: State<Application>) -> Json<Value> {
-    let consensus = state.blockchain.write().unwrap().resolve_conflicts();
+fn node_consensus(state: State<Application>) -> Result<Json<Value>, status::Custom<Json<Value>>> {
+    // Enqueue candidate chains, then wait on the queue's own handle rather
+    // than through `Blockchain` — the wait can take as long as verification
+    // does, and holding the `RwLock` read guard for that whole duration
+    // would block every writer (`/mine`, `/transaction`) behind it.
+    state.blockchain.read().unwrap().enqueue_conflicting_chains();
+    state.block_queue.wait_until_drained();
+
+    let route = state.blockchain.write().unwrap().resolve_conflicts().map_err(|err| {
+        status::Custom(Status::InternalServerError, Json(json!({
+            "message": format!("Failed to persist resolved chain: {:?}.", err)
+        })))
+    })?;
     let local_chain = &state.blockchain.read().unwrap().chain;
 
-    if consensus {
-      Json(json!({
-        "message": "Local chain has been replaced.",
-        "chain": local_chain
-      }))
-    } else {
-      Json(json!({
+    if route.enacted.is_empty() {
+      Ok(Json(json!({
         "message": "Local chain is authoritative.",
         "chain" : local_chain
-      }))
+      })))
+    } else {
+      Ok(Json(json!({
+        "message": "Local chain has been replaced.",
+        "chain": local_chain,
+        "route": route
+      })))
     }
 }
 
+#[get("/queue/status", format = "application/json")]
+fn queue_status(state: State<Application>) -> Json<QueueInfo> {
+    Json(state.blockchain.read().unwrap().queue_info())
+}
+
+#[get("/mempool", format = "application/json")]
+fn mempool_status(state: State<Application>) -> Json<MemoryPoolInfo> {
+    Json(state.blockchain.read().unwrap().mempool_info())
+}
+
 #[post("/mine", format = "application/json")]
-fn mine(state: State<Application>) -> Json<Block> {
-    let n_block = state
+fn mine(state: State<Application>) -> Result<Json<Block>, status::Custom<Json<Value>>> {
+    state
         .blockchain
         .write()
         .unwrap()
-        .mine(state.node_identifier.as_ref());
-    Json(n_block)
+        .mine(state.node_identifier.as_ref())
+        .map(Json)
+        .map_err(|err| status::Custom(Status::InternalServerError, Json(json!({
+            "message": format!("Failed to persist mined block: {:?}.", err)
+        }))))
 }
 
 #[post("/transaction", format = "application/json", data = "<transaction>")]
 fn new_transaction(
     state: State<Application>,
-    transaction: Json<Transaction>,
-) -> status::Created<Json<Value>> {
-    let new_t: Transaction = transaction.into_inner();
-    let idx = state.blockchain.write().unwrap().new_transaction(
-        new_t.sender,
-        new_t.recipient,
-        new_t.amount,
-    );
-
-    status::Created(
-        "/chain".to_owned(),
-        Some(Json(json!({
-            "message": format!("Added new transaction to block #{}.", idx)
-        }))),
-    )
+    transaction: Json<UnverifiedTransaction>,
+) -> Result<status::Created<Json<Value>>, status::Custom<Json<Value>>> {
+    let new_t: UnverifiedTransaction = transaction.into_inner();
+
+    match state.blockchain.write().unwrap().new_transaction(new_t) {
+        Ok(idx) => Ok(status::Created(
+            "/chain".to_owned(),
+            Some(Json(json!({
+                "message": format!("Added new transaction to block #{}.", idx)
+            }))),
+        )),
+        Err(err @ TransactionError::StorageFailure(_)) => Err(status::Custom(Status::InternalServerError, Json(json!({
+            "message": format!("Failed to persist transaction: {:?}.", err)
+        })))),
+        Err(err) => Err(status::Custom(Status::BadRequest, Json(json!({
+            "message": format!("Rejected transaction: {:?}.", err)
+        })))),
+    }
 }
 
 #[get("/chain", format = "application/json")]